@@ -0,0 +1,63 @@
+//! Atomic multi-operation writes, mirroring leveldb's `WriteBatch`.
+//!
+//! A `WriteBatch` accumulates `Put`/`Delete` operations and is handed to
+//! `Db::write` as a single unit: the whole batch is serialized into one
+//! log record and fsynced before any of its operations are applied to
+//! the in-memory map, so a batch either takes effect entirely or not at
+//! all.
+
+use std::collections::HashMap;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Operation<V> {
+    Put(String, V),
+    Delete(String)
+}
+
+/// A sequence of `Put`/`Delete` operations to be applied atomically.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound(deserialize = "V: DeserializeOwned"))]
+pub struct WriteBatch<V> {
+    operations: Vec<Operation<V>>
+}
+
+impl<V> WriteBatch<V> {
+    pub fn new() -> Self {
+        WriteBatch { operations: Vec::new() }
+    }
+
+    /// Queues a key/value pair to be inserted when the batch is written.
+    pub fn put(&mut self, key: String, value: V) -> &mut Self {
+        self.operations.push(Operation::Put(key, value));
+        self
+    }
+
+    /// Queues a key to be removed when the batch is written.
+    pub fn delete(&mut self, key: String) -> &mut Self {
+        self.operations.push(Operation::Delete(key));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Applies every queued operation to `data`, in order.
+    pub(crate) fn apply_to(self, data: &mut HashMap<String, V>) {
+        for op in self.operations {
+            match op {
+                Operation::Put(key, value) => { data.insert(key, value); }
+                Operation::Delete(key) => { data.remove(&key); }
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+//! A fixed header — magic bytes plus a format version number — written
+//! at the start of both the snapshot and the replay log, so a future
+//! change to either on-disk layout can be detected and refused instead
+//! of silently misread.
+
+use std::io::{Read, Write};
+
+use Result;
+
+const MAGIC: [u8; 4] = *b"MDB1";
+
+/// The on-disk format version this build writes, and the newest
+/// version it knows how to read. Bump this whenever the snapshot or
+/// log layout changes, and teach `Db::upgrade` how to migrate records
+/// written at the previous version.
+pub const CURRENT_VERSION: u32 = 1;
+
+/// Size in bytes of the header written by `write_header`.
+pub const HEADER_SIZE: usize = 8; // magic(4) + version(4)
+
+/// Writes the header at the start of a freshly (re)written file.
+pub fn write_header<W: Write>(w: &mut W, version: u32) -> Result<()> {
+    w.write_all(&MAGIC)?;
+    w.write_all(&u32_to_le(version))?;
+    Ok(())
+}
+
+/// Reads and validates the header, returning the file's format
+/// version. Fails if the magic bytes don't match, or if the version is
+/// newer than this build understands.
+pub fn read_header<R: Read>(r: &mut R) -> Result<u32> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err("not a minidb file: bad magic bytes".into());
+    }
+
+    let mut version_bytes = [0u8; 4];
+    r.read_exact(&mut version_bytes)?;
+    let version = le_to_u32(&version_bytes);
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "minidb file has format version {}, but this build only understands up to version {}; rebuild with a newer minidb",
+            version, CURRENT_VERSION).into());
+    }
+    Ok(version)
+}
+
+fn u32_to_le(v: u32) -> [u8; 4] {
+    [(v & 0xff) as u8, ((v >> 8) & 0xff) as u8, ((v >> 16) & 0xff) as u8, ((v >> 24) & 0xff) as u8]
+}
+
+fn le_to_u32(b: &[u8]) -> u32 {
+    (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
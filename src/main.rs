@@ -1,19 +1,42 @@
 #[macro_use] extern crate log;
 #[macro_use] extern crate serde_derive;
 extern crate serde;
+extern crate serde_json;
 extern crate bincode;
+extern crate ron;
 extern crate env_logger;
 
+mod batch;
+mod format;
+mod serializer;
+mod wal;
+
 use std::collections::HashMap;
+use std::collections::hash_map::{Iter, Keys, Values};
 use std::error::Error;
-use std::fs::{File, OpenOptions, create_dir_all, remove_dir_all};
+use std::fs::{self, File, OpenOptions, create_dir_all, remove_dir_all};
+use std::marker::PhantomData;
 use std::path::PathBuf;
-use std::io::{BufReader, BufWriter};
+use std::io::Read;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use batch::WriteBatch;
+use serializer::{Bincode, Serializer};
 
 
 const REPLAY_LOG: &'static str = "replay.log";
 const DB_SNAPSHOT: &'static str = "db.snapshot";
 
+/// Once the replay log grows past this many bytes, `write` triggers a
+/// compaction: a fresh snapshot is written and the log is truncated.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+fn default_compaction_threshold() -> u64 {
+    DEFAULT_COMPACTION_THRESHOLD
+}
+
 
 type Result<T> = std::result::Result<T, Box<Error>>;
 
@@ -25,7 +48,7 @@ enum Typing {
 }
 
 // The data that we want to store in the database.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct LanguageInfo {
     creator: String,
     year: u16,
@@ -33,15 +56,30 @@ struct LanguageInfo {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Db {
+#[serde(bound(deserialize = "V: DeserializeOwned"))]
+struct Db<V, S = Bincode> {
     dir: PathBuf,
     replay_log: PathBuf,
     db_snapshot: PathBuf,
-    data: HashMap<String, LanguageInfo>,
-    enable_logging: bool
+    data: HashMap<String, V>,
+    enable_logging: bool,
+    #[serde(skip, default = "default_compaction_threshold")]
+    compaction_threshold: u64,
+    #[serde(skip)]
+    log_size: u64,
+    /// Bumped once per committed write (live or replayed), so it
+    /// survives restart and `Snapshot::seq` reflects every write ever
+    /// applied to the database, not just the ones since it was loaded.
+    seq: u64,
+    #[serde(skip)]
+    log_writer: Option<wal::LogWriter>,
+    #[serde(skip)]
+    _value: PhantomData<V>,
+    #[serde(skip)]
+    _serializer: PhantomData<S>
 }
 
-impl Db {
+impl<V: Serialize + DeserializeOwned, S: Serializer> Db<V, S> {
     fn new(dir: PathBuf) -> Self {
         let replay_log = dir.join(REPLAY_LOG);
         let db_snapshot = dir.join(DB_SNAPSHOT);
@@ -50,7 +88,13 @@ impl Db {
             replay_log,
             db_snapshot,
             data: HashMap::with_capacity(1024),
-            enable_logging: true
+            enable_logging: true,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            log_size: 0,
+            seq: 0,
+            log_writer: None,
+            _value: PhantomData,
+            _serializer: PhantomData
         }
     }
 
@@ -58,14 +102,55 @@ impl Db {
     /// Initializes a new database; if `dir` looks like a database
     /// directory, load from disk, otherwise create an empty database.
     pub fn load_or_new(dir: PathBuf) -> Result<Self> {
-        if Self::is_db_dir(&dir) {
-            Self::restore_and_replay(dir)
+        let mut db = if Self::is_db_dir(&dir) {
+            Self::restore_and_replay(dir)?
         }
         else {
-            Self::create(dir)
+            Self::create(dir)?
+        };
+        db.open_log()?;
+        Ok(db)
+    }
+
+    /// Opens (or reopens) the persistent log writer used by `write`,
+    /// writing or validating the format header as needed, and records
+    /// the replay log's current size for compaction bookkeeping.
+    fn open_log(&mut self) -> Result<()> {
+        let is_empty = fs::metadata(&self.replay_log).map(|m| m.len() == 0).unwrap_or(true);
+        if is_empty {
+            let mut fd = OpenOptions::new().create(true).append(true).open(&self.replay_log)?;
+            format::write_header(&mut fd, format::CURRENT_VERSION)?;
+        } else {
+            let version = format::read_header(&mut File::open(&self.replay_log)?)?;
+            Self::require_current_version(version)?;
+        }
+
+        self.log_writer = Some(wal::LogWriter::open_append(&self.replay_log, format::HEADER_SIZE)?);
+        self.log_size = fs::metadata(&self.replay_log)?.len();
+        Ok(())
+    }
+
+    /// Rejects a snapshot or replay log written at a format version this
+    /// build doesn't know how to read in place. There is no older
+    /// version in this codebase's history yet, so the only arm here is
+    /// `CURRENT_VERSION`; when a new version is introduced, `upgrade`
+    /// (not this path) is where the conversion from the old arm lives.
+    fn require_current_version(version: u32) -> Result<()> {
+        match version {
+            format::CURRENT_VERSION => Ok(()),
+            v => Err(format!(
+                "database is at format version {}, but this build only opens version {} directly; run `upgrade` first",
+                v, format::CURRENT_VERSION).into())
         }
     }
 
+    /// Sets the replay log size, in bytes, past which `write` triggers
+    /// a compaction.
+    #[allow(dead_code)]
+    pub fn set_compaction_threshold(&mut self, bytes: u64) {
+        self.compaction_threshold = bytes;
+    }
+
 
     /// Ensures that the database directory and related files exist.
     fn is_db_dir(dir: &PathBuf) -> bool {
@@ -78,11 +163,14 @@ impl Db {
     fn restore_and_replay(dir: PathBuf) -> Result<Self> {
         debug!("Restoring database from snapshot");
         let db_snapshot = dir.join(DB_SNAPSHOT);
-        let mut db: Db =
+        let mut db: Db<V, S> =
             if db_snapshot.is_file() {
-                let fd = File::open(db_snapshot)?;
-                let mut buf_reader = BufReader::new(fd);
-                bincode::deserialize_from(&mut buf_reader, bincode::Infinite)?
+                let mut buf = Vec::new();
+                File::open(db_snapshot)?.read_to_end(&mut buf)?;
+                let mut header: &[u8] = &buf;
+                let version = format::read_header(&mut header)?;
+                Self::require_current_version(version)?;
+                S::deserialize(&buf[format::HEADER_SIZE..])?
             } else {
                 Self::new(dir)
             };
@@ -100,29 +188,53 @@ impl Db {
 
     /// Adds a new key/value pair to the database; the pair
     /// is stored in memory and in the replay log on disk.
-    pub fn add(&mut self, key: String, value: LanguageInfo) {
-        // XXX(vfoley): in real version, the replay log should remain opened.
+    pub fn add(&mut self, key: String, value: V) {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        if let Err(e) = self.write(batch) {
+            error!("{}", e);
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn delete(&mut self, key: String) {
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        if let Err(e) = self.write(batch) {
+            error!("{}", e);
+        }
+    }
+
+    /// Applies `batch` atomically: the whole batch is serialized into a
+    /// single log record and fsynced, and only then are its operations
+    /// applied to the in-memory map. If the log write fails, none of the
+    /// batch's operations take effect. Once the replay log grows past
+    /// `compaction_threshold`, a compaction is triggered automatically.
+    pub fn write(&mut self, batch: WriteBatch<V>) -> Result<()> {
         if self.enable_logging {
-            let fd = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&self.replay_log)
-                .unwrap();
-            let mut buf_writer = BufWriter::new(fd);
-            let pair = (&key, &value);
-
-            let ser_result = bincode::serialize_into(
-                &mut buf_writer, &pair, bincode::Infinite);
-            match ser_result {
-                Ok(()) => (),
-                Err(e) => { error!("{}", e); }
+            let payload = S::serialize(&batch)?;
+            {
+                let writer = self.log_writer.as_mut()
+                    .expect("replay log should be open; Db is constructed via load_or_new");
+                writer.add_record(&payload)?;
+                writer.flush()?;
             }
+            self.log_size = fs::metadata(&self.replay_log)?.len();
+        }
+        batch.apply_to(&mut self.data);
+        self.seq += 1;
+        if self.enable_logging && self.log_size > self.compaction_threshold {
+            // Compact against the state *after* this batch: `compact`
+            // snapshots `self.data` as it stands now and truncates the
+            // log that just durably recorded this write, so the
+            // snapshot must already include it or it's lost for good.
+            self.compact()?;
         }
-        self.data.insert(key, value);
+        Ok(())
     }
 
     #[allow(dead_code)]
-    pub fn get(&self, key: &str) -> Option<&LanguageInfo> {
+    pub fn get(&self, key: &str) -> Option<&V> {
         self.data.get(key)
     }
 
@@ -131,29 +243,177 @@ impl Db {
         self.data.len()
     }
 
-    pub fn save(&self) {
-        let fd = File::create(&self.db_snapshot).unwrap();
-        let mut buf_writer = BufWriter::new(fd);
-        let _ = bincode::serialize_into(&mut buf_writer, &self, bincode::Infinite);
-        let _ = File::create(&self.replay_log); // empty replay log
+    /// Iterates over the keys and values currently committed to the
+    /// database. Like `keys`/`values`, this reflects the live map, not
+    /// a point-in-time view; for that, see `snapshot`.
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Iter<'_, String, V> {
+        self.data.iter()
+    }
+
+    #[allow(dead_code)]
+    pub fn keys(&self) -> Keys<'_, String, V> {
+        self.data.keys()
+    }
+
+    #[allow(dead_code)]
+    pub fn values(&self) -> Values<'_, String, V> {
+        self.data.values()
+    }
+
+    /// Captures a logically consistent, read-only view of the database
+    /// as of this call: a copy of the committed key/value pairs tagged
+    /// with the sequence number of the last write applied to it.
+    /// Writes made to the `Db` afterward are not reflected in the
+    /// snapshot.
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> Snapshot<V> where V: Clone {
+        Snapshot {
+            seq: self.seq,
+            data: self.data.clone()
+        }
+    }
+
+    pub fn save(&mut self) {
+        if let Err(e) = self.compact() {
+            error!("{}", e);
+        }
+    }
+
+    /// Writes a fresh snapshot and truncates the replay log, each
+    /// swapped in atomically (write to a temp file, then rename) so a
+    /// crash mid-compaction never loses committed data.
+    ///
+    /// The two renames are not atomic *with each other*: the snapshot
+    /// is written (and put in place by the rename) before the log is
+    /// truncated, deliberately, so a crash between them leaves the
+    /// *old*, untruncated log sitting next to the *new* snapshot that
+    /// already reflects every record in it. The only consequence is
+    /// that `restore_and_replay` replays that log again on top of the
+    /// snapshot: every `Put`/`Delete` is idempotent, so no data is
+    /// lost, but `replay`'s `seq` bump is not, so `seq` ends up
+    /// double-counting those already-snapshotted records and never
+    /// self-corrects on a later compaction. Swapping the rename order
+    /// would trade that cosmetic inflation for outright data loss
+    /// instead (a crash between renames would then leave a stale
+    /// snapshot next to an already-truncated log, losing every record
+    /// written since the *previous* compaction), which is worse, so
+    /// this order is intentional.
+    fn compact(&mut self) -> Result<()> {
+        debug!("compacting: snapshotting {} entries and truncating replay log", self.data.len());
+
+        let tmp_snapshot = self.db_snapshot.with_extension("snapshot.tmp");
+        let mut snapshot_bytes = Vec::new();
+        format::write_header(&mut snapshot_bytes, format::CURRENT_VERSION)?;
+        snapshot_bytes.extend_from_slice(&S::serialize(&self)?);
+        fs::write(&tmp_snapshot, &snapshot_bytes)?;
+        fs::rename(&tmp_snapshot, &self.db_snapshot)?;
+
+        let tmp_log = self.replay_log.with_extension("log.tmp");
+        let mut log_bytes = Vec::new();
+        format::write_header(&mut log_bytes, format::CURRENT_VERSION)?;
+        fs::write(&tmp_log, &log_bytes)?;
+        fs::rename(&tmp_log, &self.replay_log)?;
+
+        self.open_log()
     }
 
     pub fn replay(&mut self) -> Result<()> {
-        let prev_logging = self.enable_logging;
-        self.enable_logging = false;
-        let fd = File::open(&self.replay_log)?;
-        let mut buf_reader = BufReader::new(fd);
+        let mut buf = Vec::new();
+        File::open(&self.replay_log)?.read_to_end(&mut buf)?;
+
+        let mut header: &[u8] = &buf;
+        let version = format::read_header(&mut header)?;
+        Self::require_current_version(version)?;
+        let body = &buf[format::HEADER_SIZE..];
+
+        let mut reader = wal::LogReader::new(body);
         loop {
-            let res: bincode::Result<(String, LanguageInfo)> =
-                bincode::deserialize_from(&mut buf_reader, bincode::Infinite);
-            match res {
-                Ok((name, person)) => { self.add(name, person); }
-                Err(_) => { break; }
+            match reader.next_record()? {
+                Some(payload) => {
+                    let batch: WriteBatch<V> = S::deserialize(&payload)?;
+                    batch.apply_to(&mut self.data);
+                    self.seq += 1;
+                }
+                None => {
+                    if reader.pos() < body.len() {
+                        warn!("truncating torn write in replay log ({} of {} bytes kept)",
+                              reader.pos(), body.len());
+                        OpenOptions::new().write(true).open(&self.replay_log)?
+                            .set_len((format::HEADER_SIZE + reader.pos()) as u64)?;
+                    }
+                    break;
+                }
             }
         }
-        self.enable_logging = prev_logging;
+
         return Ok(());
     }
+
+    /// Migrates a database directory to `format::CURRENT_VERSION`,
+    /// dispatching on the version recorded in its replay log header.
+    /// A no-op if the database is already current (or doesn't exist
+    /// yet). There is no older format in this codebase's history yet,
+    /// so there's nothing to convert; when one is introduced, add a
+    /// match arm here that reads the old schema and calls `compact` to
+    /// rewrite it at the current version, rather than teaching the
+    /// regular open path (`restore_and_replay`/`replay`) to tolerate it.
+    pub fn upgrade(dir: PathBuf) -> Result<()> {
+        let replay_log = dir.join(REPLAY_LOG);
+        let version = if replay_log.is_file() {
+            format::read_header(&mut File::open(&replay_log)?)?
+        } else {
+            format::CURRENT_VERSION
+        };
+
+        match version {
+            format::CURRENT_VERSION => {
+                debug!("database at {:?} is already at version {}; nothing to upgrade", dir, format::CURRENT_VERSION);
+                Ok(())
+            }
+            v => Err(format!(
+                "don't know how to migrate a version {} database to version {} yet",
+                v, format::CURRENT_VERSION).into())
+        }
+    }
+}
+
+/// A point-in-time, read-only view of a `Db`, captured by `Db::snapshot`.
+/// Unaffected by any `add`/`delete`/`write` made to the originating
+/// `Db` after the snapshot was taken.
+#[derive(Debug)]
+struct Snapshot<V> {
+    seq: u64,
+    data: HashMap<String, V>
+}
+
+impl<V> Snapshot<V> {
+    /// The originating `Db`'s sequence number at the time this
+    /// snapshot was captured.
+    #[allow(dead_code)]
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.data.get(key)
+    }
+
+    #[allow(dead_code)]
+    pub fn iter(&self) -> Iter<'_, String, V> {
+        self.data.iter()
+    }
+
+    #[allow(dead_code)]
+    pub fn keys(&self) -> Keys<'_, String, V> {
+        self.data.keys()
+    }
+
+    #[allow(dead_code)]
+    pub fn values(&self) -> Values<'_, String, V> {
+        self.data.values()
+    }
 }
 
 fn main() {
@@ -164,7 +424,7 @@ fn main() {
     let _ = remove_dir_all(DB_DIR);
 
     {
-        let mut db = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        let mut db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
 
         db.add("C".to_string(), LanguageInfo {
             creator: "Dennis Ritchie".to_string(),
@@ -181,17 +441,17 @@ fn main() {
     }
 
     {
-        let db = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        let db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
         println!("DB loaded from log only: {:#?}", db);
     }
 
     {
-        let db = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        let mut db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
         db.save();
     }
 
     {
-        let mut db = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        let mut db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
         println!("DB loaded from snapshot only: {:#?}", db);
 
         db.add("Go".to_string(), LanguageInfo {
@@ -202,7 +462,98 @@ fn main() {
     }
 
     {
-        let db = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        let db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
         println!("DB loaded from snapshot + replay: {:#?}", db);
     }
+
+    {
+        let mut db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put("Rust".to_string(), LanguageInfo {
+            creator: "Graydon Hoare".to_string(),
+            year: 2010,
+            typing: Typing::Static
+        });
+        batch.delete("Python".to_string());
+        db.write(batch).unwrap();
+
+        println!("DB after atomic batch write: {:#?}", db);
+    }
+
+    {
+        // The snapshot and replay log can also be written in a
+        // human-readable format, handy for debugging and interchange.
+        const JSON_DB_DIR: &'static str = "/tmp/minidb-json";
+        let _ = remove_dir_all(JSON_DB_DIR);
+
+        let mut db: Db<LanguageInfo, serializer::Json> =
+            Db::load_or_new(PathBuf::from(JSON_DB_DIR)).unwrap();
+        db.add("Lisp".to_string(), LanguageInfo {
+            creator: "John McCarthy".to_string(),
+            year: 1958,
+            typing: Typing::Dynamic
+        });
+
+        println!("JSON-backed DB: {:#?}", db);
+    }
+
+    {
+        // `upgrade` migrates a database to the current format version;
+        // it's a no-op here since this database is already current.
+        Db::<LanguageInfo, Bincode>::upgrade(PathBuf::from(DB_DIR)).unwrap();
+        let db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        println!("DB after upgrade: {:#?}", db);
+    }
+
+    {
+        // `snapshot` freezes a read-only view that later writes don't
+        // affect; `iter`/`keys`/`values` work the same way on both the
+        // live `Db` and a `Snapshot`.
+        let mut db: Db<LanguageInfo> = Db::load_or_new(PathBuf::from(DB_DIR)).unwrap();
+        let snapshot = db.snapshot();
+
+        db.add("Zig".to_string(), LanguageInfo {
+            creator: "Andrew Kelley".to_string(),
+            year: 2016,
+            typing: Typing::Static
+        });
+
+        println!("live keys: {:?}", db.keys().collect::<Vec<_>>());
+        println!("snapshot (seq {}) keys: {:?}", snapshot.seq(), snapshot.keys().collect::<Vec<_>>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A write that pushes the replay log past `compaction_threshold`
+    /// triggers an auto-compaction; that write must still be part of
+    /// the database after a reload, not just visible for the rest of
+    /// the current process.
+    #[test]
+    fn write_that_triggers_compaction_survives_reload() {
+        let dir = env::temp_dir().join("minidb-test-compaction-survives-reload");
+        let _ = remove_dir_all(&dir);
+
+        {
+            let mut db: Db<String> = Db::load_or_new(dir.clone()).unwrap();
+            db.set_compaction_threshold(50);
+            db.add("A".to_string(), "short".to_string());
+            // Long enough, on top of "A"'s own record, to push the log
+            // past the 50-byte threshold and trigger a compaction
+            // mid-write.
+            db.add("B".to_string(), "a value long enough to cross the compaction threshold".to_string());
+            assert_eq!(db.keys().count(), 2);
+        }
+
+        let db: Db<String> = Db::load_or_new(dir.clone()).unwrap();
+        let mut keys: Vec<&String> = db.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec![&"A".to_string(), &"B".to_string()]);
+
+        let _ = remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,61 @@
+//! Pluggable (de)serialization backends for the snapshot and replay log.
+//!
+//! `Db` is generic over a `Serializer` so callers can pick a compact
+//! binary format for production use, or a human-readable one for
+//! debugging and interchange, without touching the storage engine
+//! itself. Every snapshot and log record goes through the chosen
+//! backend's `serialize`/`deserialize`.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use Result;
+
+pub trait Serializer {
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T>;
+}
+
+/// Compact binary format; the default backend.
+#[derive(Debug, Clone, Copy)]
+pub struct Bincode;
+
+impl Serializer for Bincode {
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value, bincode::Infinite)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Human-readable JSON format.
+#[derive(Debug, Clone, Copy)]
+pub struct Json;
+
+impl Serializer for Json {
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// Human-readable RON (Rusty Object Notation) format.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct Ron;
+
+impl Serializer for Ron {
+    fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(ron::ser::to_string(value)?.into_bytes())
+    }
+
+    fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+        let s = ::std::str::from_utf8(bytes)?;
+        Ok(ron::de::from_str(s)?)
+    }
+}